@@ -0,0 +1,219 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+use log::info;
+use ring::aead::{self, BoundKey};
+use ring::agreement;
+use ring::hkdf;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::nsm;
+use vtok_rpc::api::schema;
+
+/// Size of the per-handshake challenge nonce, in bytes.
+const NONCE_LEN: usize = 32;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A measured PCR didn't match the value configured in `config::Enclave`.
+    PcrMismatch(usize),
+    KeyAgreementError,
+    SealError,
+    OpenError,
+    SerializeError(serde_json::Error),
+    /// Attestation is configured (PCRs are set) but no session has been
+    /// established yet, e.g. because re-attestation failed after a watchdog
+    /// rebuild. Callers must not fall back to plaintext in this case.
+    SessionRequired,
+    /// The attestation document failed COSE/certificate-chain verification,
+    /// or was not bound to the nonce this handshake sent.
+    DocumentVerificationFailed(nsm::Error),
+}
+
+struct NonceCounter(AtomicU64);
+
+impl aead::NonceSequence for NonceCounter {
+    fn advance(&mut self) -> Result<aead::Nonce, ring::error::Unspecified> {
+        let count = self.0.fetch_add(1, Ordering::Relaxed);
+        let mut bytes = [0u8; aead::NONCE_LEN];
+        bytes[4..].copy_from_slice(&count.to_be_bytes());
+        Ok(aead::Nonce::assume_unique_for_key(bytes))
+    }
+}
+
+/// The host side of the ephemeral key agreement, generated before the
+/// `Attest` RPC is sent. `public_bytes()` must be transmitted to the enclave
+/// as part of that request so it can compute the same shared secret; without
+/// it the enclave has no way to derive the session key we derive here.
+///
+/// Also carries a fresh `nonce`, sent in the same request and echoed back
+/// inside the signed attestation document, so a captured-and-replayed
+/// document from an earlier handshake can't be passed off as live.
+pub struct Handshake {
+    private: agreement::EphemeralPrivateKey,
+    public_bytes: Vec<u8>,
+    nonce: Vec<u8>,
+}
+
+impl Handshake {
+    pub fn generate() -> Result<Self, Error> {
+        let rng = SystemRandom::new();
+        let private = agreement::EphemeralPrivateKey::generate(&agreement::X25519, &rng)
+            .map_err(|_| Error::KeyAgreementError)?;
+        let public_bytes = private
+            .compute_public_key()
+            .map_err(|_| Error::KeyAgreementError)?
+            .as_ref()
+            .to_vec();
+
+        let mut nonce = vec![0u8; NONCE_LEN];
+        rng.fill(&mut nonce).map_err(|_| Error::KeyAgreementError)?;
+
+        Ok(Handshake {
+            private,
+            public_bytes,
+            nonce,
+        })
+    }
+
+    /// Bytes to send to the enclave as part of the `Attest` request.
+    pub fn public_bytes(&self) -> &[u8] {
+        &self.public_bytes
+    }
+
+    /// The challenge nonce to send alongside `public_bytes()`; the enclave's
+    /// attestation document must echo it back for `establish` to trust it.
+    pub fn nonce(&self) -> &[u8] {
+        &self.nonce
+    }
+}
+
+/// A session key established between host and enclave, derived from an
+/// attested ephemeral key agreement. Used to seal the bodies of requests
+/// that carry secrets (token PINs, envelope keys) so they never cross the
+/// vsock link in plaintext.
+pub struct Session {
+    key: [u8; 32],
+    send_nonce: AtomicU64,
+}
+
+impl Session {
+    /// Verify `raw_document` — the bytes the enclave's NSM returned from its
+    /// `Attest` call — against `root_cert_der` and `handshake.nonce()`, check
+    /// its PCR measurements against `expected_pcrs`, then derive a symmetric
+    /// session key from `handshake`'s ephemeral private key and the
+    /// document's (now-verified) public key.
+    ///
+    /// The PCRs and public key inside an attestation document prove nothing
+    /// by themselves — the EIF is public, so its PCRs are reproducible by
+    /// anyone, and a MITM on the still-plaintext vsock link could otherwise
+    /// forge a document pairing the expected PCRs with an attacker-chosen
+    /// key. Trust only follows once the document's COSE signature chains to
+    /// `root_cert_der` (the AWS Nitro Enclaves root CA) and its nonce field
+    /// matches this handshake's, which rules out both forgery and replay of
+    /// a previously captured document.
+    ///
+    /// `handshake.public_bytes()` and `handshake.nonce()` must already have
+    /// been sent to the enclave (as part of the `Attest` request that
+    /// produced `raw_document`) so both sides derive the same shared secret
+    /// and the nonce check is meaningful.
+    pub fn establish(
+        handshake: Handshake,
+        raw_document: &[u8],
+        root_cert_der: &[u8],
+        expected_pcrs: &[(usize, Vec<u8>)],
+    ) -> Result<Self, Error> {
+        let doc = nsm::verify(raw_document, root_cert_der, &handshake.nonce)
+            .map_err(Error::DocumentVerificationFailed)?;
+
+        for (index, expected) in expected_pcrs {
+            match doc.pcrs.get(index) {
+                Some(actual) if actual == expected => {}
+                _ => return Err(Error::PcrMismatch(*index)),
+            }
+        }
+
+        let enclave_public = agreement::UnparsedPublicKey::new(&agreement::X25519, &doc.public_key);
+        let host_public_bytes = handshake.public_bytes;
+
+        let key = agreement::agree_ephemeral(
+            handshake.private,
+            &enclave_public,
+            Error::KeyAgreementError,
+            |shared_secret| {
+                let salt = hkdf::Salt::new(hkdf::HKDF_SHA256, &host_public_bytes);
+                let mut out = [0u8; 32];
+                salt.extract(shared_secret)
+                    .expand(&[b"p11ne-rpc-session"], hkdf::HKDF_SHA256)
+                    .and_then(|okm| okm.fill(&mut out))
+                    .map_err(|_| Error::KeyAgreementError)?;
+                Ok(out)
+            },
+        )?;
+
+        info!("Attestation verified; RPC session key established");
+        Ok(Session {
+            key,
+            send_nonce: AtomicU64::new(0),
+        })
+    }
+
+    /// Serialize and seal `request`'s body so it can be sent in place of the
+    /// plaintext request.
+    pub fn seal_request(&self, request: &schema::ApiRequest) -> Result<schema::ApiRequest, Error> {
+        let plaintext = serde_json::to_vec(request).map_err(Error::SerializeError)?;
+        let unbound = aead::UnboundKey::new(&aead::AES_256_GCM, &self.key)
+            .map_err(|_| Error::SealError)?;
+        let nonce_start = self.send_nonce.fetch_add(1, Ordering::Relaxed);
+        let mut sealing_key =
+            aead::SealingKey::new(unbound, NonceCounter(AtomicU64::new(nonce_start)));
+
+        let mut in_out = plaintext;
+        sealing_key
+            .seal_in_place_append_tag(aead::Aad::empty(), &mut in_out)
+            .map_err(|_| Error::SealError)?;
+
+        Ok(schema::ApiRequest::Sealed {
+            nonce: nonce_start,
+            ciphertext: in_out,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::aead::NonceSequence;
+
+    #[test]
+    fn nonce_counter_never_repeats_a_nonce() {
+        let mut counter = NonceCounter(AtomicU64::new(0));
+        let first = counter.advance().unwrap();
+        let second = counter.advance().unwrap();
+        let third = counter.advance().unwrap();
+        // `aead::Nonce` doesn't expose equality, so compare the encoded bytes.
+        let bytes = |n: aead::Nonce| n.as_ref().to_vec();
+        assert_ne!(bytes(first), bytes(second));
+        assert_ne!(bytes(second), bytes(third));
+        assert_ne!(bytes(first), bytes(third));
+    }
+
+    #[test]
+    fn seal_request_advances_send_nonce_on_every_call() {
+        let session = Session {
+            key: [7u8; 32],
+            send_nonce: AtomicU64::new(0),
+        };
+
+        let first = session.seal_request(&schema::ApiRequest::DescribeDevice).unwrap();
+        let second = session.seal_request(&schema::ApiRequest::DescribeDevice).unwrap();
+
+        let nonce_of = |req: schema::ApiRequest| match req {
+            schema::ApiRequest::Sealed { nonce, .. } => nonce,
+            _ => panic!("expected a Sealed request"),
+        };
+        let (first_nonce, second_nonce) = (nonce_of(first), nonce_of(second));
+        assert_ne!(first_nonce, second_nonce, "each call must use a fresh nonce");
+        assert!(second_nonce > first_nonce, "nonces must be monotonically increasing");
+    }
+}
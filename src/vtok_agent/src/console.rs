@@ -0,0 +1,90 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+use log::warn;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// Attaches to an enclave's console/serial stream (via `nitro-cli console`)
+/// and continuously drains it to a host-side log file, for diagnosing
+/// `wait_boot()`/attestation failures in `debug_mode`.
+///
+/// The draining thread holds the subprocess's stdout open for as long as
+/// `ConsoleReader` is alive, regardless of whether anything is tailing the
+/// log file at any given moment — the same way a managed pty keeps its
+/// subordinate fd open so a client can detach and re-attach without losing
+/// output or killing the session.
+pub struct ConsoleReader {
+    child: Child,
+    pump: Option<thread::JoinHandle<()>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl ConsoleReader {
+    pub fn spawn(enclave_id: &str, log_path: PathBuf) -> io::Result<Self> {
+        let mut child = Command::new("nitro-cli")
+            .args(&["console", "--enclave-id", enclave_id])
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdout = child.stdout.take().expect("piped stdout");
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let pump = thread::spawn({
+            let stop = stop.clone();
+            move || Self::pump(stdout, log_path, stop)
+        });
+
+        Ok(ConsoleReader {
+            child,
+            pump: Some(pump),
+            stop,
+        })
+    }
+
+    fn pump(mut stdout: ChildStdout, log_path: PathBuf, stop: Arc<AtomicBool>) {
+        let mut log = match OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)
+        {
+            Ok(file) => file,
+            Err(err) => {
+                warn!("Failed to open console log {:?}: {:?}", log_path, err);
+                return;
+            }
+        };
+
+        let mut buf = [0u8; 4096];
+        while !stop.load(Ordering::Relaxed) {
+            match stdout.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    if let Err(err) = log.write_all(&buf[..n]) {
+                        warn!("Failed writing enclave console log: {:?}", err);
+                        break;
+                    }
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    warn!("Enclave console read error: {:?}", err);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ConsoleReader {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.child.kill().unwrap_or_default();
+        self.child.wait().unwrap_or_default();
+        if let Some(pump) = self.pump.take() {
+            pump.join().unwrap_or_default();
+        }
+    }
+}
@@ -2,21 +2,30 @@
 // SPDX-License-Identifier: Apache-2.0
 use log::{info, warn};
 use nix::sys::signal;
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd;
 use std::fs::OpenOptions;
 use std::io::Write;
+use std::path::PathBuf;
 use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::{Duration, Instant};
 
+use super::attestation::{self, Session};
+use super::console::ConsoleReader;
 use super::defs;
 use super::ne;
+use super::transport::{RpcClient, TransportCallError};
+use super::watchdog::{self, Watchdog};
 use crate::{config, util};
 use vtok_rpc::api::schema;
-use vtok_rpc::{HttpTransport, Transport, VsockAddr, VsockStream};
 
 #[derive(Debug)]
 pub enum Error {
+    AttestationError(attestation::Error),
     NitroCliError(ne::Error),
+    NsmRootCertError(std::io::Error),
     P11KitSetupError(std::io::Error),
     RpcConnectError(std::io::Error),
     RpcTransportError(vtok_rpc::TransportError),
@@ -24,16 +33,60 @@ pub enum Error {
     VsockProxyError(Option<i32>),
 }
 
-pub struct P11neEnclave {
+/// State that gets rebuilt whenever the watchdog recovers a crashed enclave.
+struct EnclaveState {
     cid: u32,
     pid: i32,
+    enclave_id: String,
+}
+
+struct Inner {
+    state: Mutex<EnclaveState>,
     boot_timeout: std::time::Duration,
+    shutdown_timeout: std::time::Duration,
     rpc_port: u32,
     attestation_retry_count: usize,
+    enclave_config: config::Enclave,
+    /// Tokens applied via `add_token()`, kept around so the watchdog can
+    /// replay the PKCS#11 surface after an enclave rebuild.
+    applied_tokens: Mutex<Vec<schema::Token>>,
+    rpc_client: RpcClient,
+    /// Set once attestation succeeds; `None` means either attestation is
+    /// disabled (no PCRs configured) or hasn't succeeded yet (it hasn't run,
+    /// or the last attempt, e.g. after a watchdog rebuild, failed).
+    session: Mutex<Option<Session>>,
+    /// Debug-mode console-attachment reader; re-spawned against the new
+    /// enclave id whenever the watchdog rebuilds the enclave.
+    console: Mutex<Option<ConsoleReader>>,
+    /// p11-kit module name for this enclave's `/etc/pkcs11/modules/*.module`
+    /// entry. Replica pools give each member a distinct name so they can
+    /// coexist as separate PKCS#11 remotes.
+    module_name: String,
+}
+
+pub struct P11neEnclave {
+    inner: Arc<Inner>,
+    watchdog: Mutex<Option<Watchdog>>,
 }
 
 impl P11neEnclave {
     pub fn new(enclave_config: config::Enclave) -> Result<Self, Error> {
+        let inner = Arc::new(Self::build(enclave_config)?);
+        inner.start_console_reader();
+        let enclave = Self {
+            inner,
+            watchdog: Mutex::new(None),
+        };
+        enclave.start_watchdog();
+        Ok(enclave)
+    }
+
+    fn build(enclave_config: config::Enclave) -> Result<Inner, Error> {
+        let module_name = enclave_config
+            .p11_module_name
+            .clone()
+            .unwrap_or_else(|| defs::P11_MODULE_NAME.to_string());
+
         let eri = ne::run_enclave(
             enclave_config
                 .image_path
@@ -42,18 +95,16 @@ impl P11neEnclave {
                 .unwrap_or(defs::DEFAULT_EIF_PATH),
             enclave_config.cpu_count,
             enclave_config.memory_mib,
+            enclave_config.debug_mode.unwrap_or(false),
         )
         .map_err(Error::NitroCliError)?;
 
-        info!("Setting up p11-kit config");
+        info!("Setting up p11-kit config for {}", module_name);
         OpenOptions::new()
             .write(true)
             .create(true)
             .truncate(true)
-            .open(format!(
-                "/etc/pkcs11/modules/{}.module",
-                defs::P11_MODULE_NAME
-            ))
+            .open(format!("/etc/pkcs11/modules/{}.module", module_name))
             .and_then(|mut file| {
                 file.write(
                     format!(
@@ -62,7 +113,7 @@ impl P11neEnclave {
                         enclave_config
                             .p11kit_port
                             .unwrap_or(defs::DEFAULT_P11KIT_PORT),
-                        defs::P11_MODULE_NAME,
+                        module_name,
                     )
                     .as_bytes(),
                 )
@@ -82,43 +133,83 @@ impl P11neEnclave {
                 }
             })?;
 
-        Ok(Self {
+        Ok(Inner {
             // TODO: replace these rudimentary casts with proper checks/conversions.
-            cid: eri.enclave_cid as u32,
-            pid: eri.process_id as i32,
+            state: Mutex::new(EnclaveState {
+                cid: eri.enclave_cid as u32,
+                pid: eri.process_id as i32,
+                enclave_id: eri.enclave_id.clone(),
+            }),
             boot_timeout: std::time::Duration::from_millis(
                 enclave_config
                     .boot_timeout_ms
                     .unwrap_or(defs::DEFAULT_ENCLAVE_BOOT_TIMEOUT_MS),
             ),
+            shutdown_timeout: std::time::Duration::from_millis(
+                enclave_config
+                    .shutdown_timeout_ms
+                    .unwrap_or(defs::DEFAULT_ENCLAVE_SHUTDOWN_TIMEOUT_MS),
+            ),
             rpc_port: enclave_config.rpc_port.unwrap_or(defs::DEFAULT_RPC_PORT),
             attestation_retry_count: enclave_config
                 .attestation_retry_count
                 .unwrap_or(defs::DEFAULT_ATTESTATION_RETRY_COUNT),
+            applied_tokens: Mutex::new(Vec::new()),
+            rpc_client: RpcClient::new(),
+            session: Mutex::new(None),
+            console: Mutex::new(None),
+            module_name,
+            enclave_config,
         })
     }
 
-    pub fn wait_boot(&self) -> bool {
-        let limit = Instant::now() + self.boot_timeout;
-        let poll_dur = Duration::from_millis(100);
-        while Instant::now() < limit {
-            if let Ok(Ok(_)) = self.rpc(&schema::ApiRequest::DescribeDevice) {
-                return true;
-            }
-            if let Err(util::SleepError::UserExit) = util::interruptible_sleep(poll_dur) {
-                return false;
-            }
+    fn start_watchdog(&self) {
+        if !self.inner.enclave_config.watchdog_enabled.unwrap_or(false) {
+            return;
         }
-        false
+        let config = watchdog::WatchdogConfig {
+            probe_mode: if self.inner.enclave_config.watchdog_use_heartbeat.unwrap_or(false) {
+                watchdog::ProbeMode::Heartbeat
+            } else {
+                watchdog::ProbeMode::Rpc
+            },
+            probe_interval: Duration::from_millis(
+                self.inner
+                    .enclave_config
+                    .watchdog_probe_interval_ms
+                    .unwrap_or(defs::DEFAULT_WATCHDOG_PROBE_INTERVAL_MS),
+            ),
+            failure_threshold: self
+                .inner
+                .enclave_config
+                .watchdog_failure_threshold
+                .unwrap_or(defs::DEFAULT_WATCHDOG_FAILURE_THRESHOLD),
+            heartbeat_port: self
+                .inner
+                .enclave_config
+                .watchdog_heartbeat_port
+                .unwrap_or(defs::DEFAULT_WATCHDOG_HEARTBEAT_PORT),
+        };
+        *self.watchdog.lock().unwrap() = Some(Watchdog::spawn(self.inner.clone(), config));
+    }
+
+    pub fn wait_boot(&self) -> bool {
+        self.inner.wait_ready() && self.inner.establish_session()
     }
 
     pub fn pid(&self) -> i32 {
-        self.pid
+        self.inner.state.lock().unwrap().pid
     }
 
     pub fn add_token(&self, token: schema::Token) -> Result<schema::ApiResponse, Error> {
         info!("Printing token {:?}", token);
-        self.retry_rpc(&schema::ApiRequest::AddToken { token })
+        let res = self.inner.retry_rpc_sealed(&schema::ApiRequest::AddToken {
+            token: token.clone(),
+        });
+        if matches!(&res, Ok(res) if res.is_ok()) {
+            self.inner.applied_tokens.lock().unwrap().push(token);
+        }
+        res
     }
 
     pub fn refresh_token(
@@ -127,7 +218,7 @@ impl P11neEnclave {
         pin: String,
         envelope_key: schema::EnvelopeKey,
     ) -> Result<schema::ApiResponse, Error> {
-        self.retry_rpc(&schema::ApiRequest::RefreshToken {
+        self.inner.retry_rpc_sealed(&schema::ApiRequest::RefreshToken {
             label,
             pin,
             envelope_key,
@@ -135,7 +226,18 @@ impl P11neEnclave {
     }
 
     pub fn remove_token(&self, label: String, pin: String) -> Result<schema::ApiResponse, Error> {
-        self.rpc(&schema::ApiRequest::RemoveToken { label, pin })
+        let res = self.inner.retry_rpc_sealed(&schema::ApiRequest::RemoveToken {
+            label: label.clone(),
+            pin,
+        });
+        if matches!(&res, Ok(res) if res.is_ok()) {
+            self.inner
+                .applied_tokens
+                .lock()
+                .unwrap()
+                .retain(|token| token.label != label);
+        }
+        res
     }
 
     pub fn update_token(
@@ -144,7 +246,17 @@ impl P11neEnclave {
         pin: String,
         token: schema::Token,
     ) -> Result<schema::ApiResponse, Error> {
-        self.retry_rpc(&schema::ApiRequest::UpdateToken { label, pin, token })
+        let res = self.inner.retry_rpc_sealed(&schema::ApiRequest::UpdateToken {
+            label: label.clone(),
+            pin,
+            token: token.clone(),
+        });
+        if matches!(&res, Ok(res) if res.is_ok()) {
+            let mut applied = self.inner.applied_tokens.lock().unwrap();
+            applied.retain(|existing| existing.label != label);
+            applied.push(token);
+        }
+        res
     }
 
     pub fn describe_token(&self, label: String, pin: String) -> Result<schema::ApiResponse, Error> {
@@ -152,10 +264,71 @@ impl P11neEnclave {
     }
 
     fn retry_rpc(&self, request: &schema::ApiRequest) -> Result<schema::ApiResponse, Error> {
+        self.inner.retry_rpc(request)
+    }
+
+    fn rpc(&self, request: &schema::ApiRequest) -> Result<schema::ApiResponse, Error> {
+        self.inner.rpc(request)
+    }
+}
+
+impl Inner {
+    /// Attach a debug-mode console reader for the current enclave id, if
+    /// `debug_mode` is configured. Replaces any previously-attached reader.
+    fn start_console_reader(&self) {
+        if !self.enclave_config.debug_mode.unwrap_or(false) {
+            return;
+        }
+        let enclave_id = self.state.lock().unwrap().enclave_id.clone();
+        let log_path = self
+            .enclave_config
+            .debug_console_log_path
+            .as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(defs::DEFAULT_DEBUG_CONSOLE_LOG_PATH));
+
+        match ConsoleReader::spawn(&enclave_id, log_path.clone()) {
+            Ok(reader) => {
+                info!("Attached debug console reader, logging to {:?}", log_path);
+                *self.console.lock().unwrap() = Some(reader);
+            }
+            Err(err) => warn!("Failed to attach debug console reader: {:?}", err),
+        }
+    }
+
+    fn wait_ready(&self) -> bool {
+        let limit = Instant::now() + self.boot_timeout;
+        let poll_dur = Duration::from_millis(100);
+        while Instant::now() < limit {
+            if let Ok(Ok(_)) = self.rpc(&schema::ApiRequest::DescribeDevice) {
+                return true;
+            }
+            if let Err(util::SleepError::UserExit) = util::interruptible_sleep(poll_dur) {
+                return false;
+            }
+        }
+        false
+    }
+
+    fn retry_rpc(&self, request: &schema::ApiRequest) -> Result<schema::ApiResponse, Error> {
+        self.retry(|| self.rpc(request))
+    }
+
+    /// Like `retry_rpc`, but seals the request to the attestation session key
+    /// (when one has been established) before sending it. Use this for calls
+    /// whose bodies carry secrets (token PINs, envelope keys).
+    fn retry_rpc_sealed(&self, request: &schema::ApiRequest) -> Result<schema::ApiResponse, Error> {
+        self.retry(|| self.rpc_sealed(request))
+    }
+
+    fn retry(
+        &self,
+        mut attempt: impl FnMut() -> Result<schema::ApiResponse, Error>,
+    ) -> Result<schema::ApiResponse, Error> {
         let mut count = 1;
         loop {
             // Transport errors are non-recoverable.
-            let res = self.rpc(request)?;
+            let res = attempt()?;
             if res.is_ok() || count == self.attestation_retry_count {
                 return Ok(res);
             }
@@ -173,31 +346,275 @@ impl P11neEnclave {
     }
 
     fn rpc(&self, request: &schema::ApiRequest) -> Result<schema::ApiResponse, Error> {
-        VsockStream::connect(VsockAddr {
-            cid: self.cid,
-            port: self.rpc_port,
-        })
-        .map_err(Error::RpcConnectError)
-        .map(|stream| HttpTransport::new(stream, schema::API_URL))
-        .and_then(|mut xport| {
-            xport
-                .send_request(request)
-                .map_err(Error::RpcTransportError)?;
-            xport.recv_response().map_err(Error::RpcTransportError)
-        })
+        let cid = self.state.lock().unwrap().cid;
+        self.rpc_client
+            .call(cid, self.rpc_port, request)
+            .map_err(|err| match err {
+                TransportCallError::Connect(err) => Error::RpcConnectError(err),
+                TransportCallError::Transport(err) => Error::RpcTransportError(err),
+            })
+    }
+
+    /// Whether this enclave is configured to attest, i.e. whether
+    /// secret-bearing RPCs are expected to go out sealed rather than in
+    /// plaintext.
+    fn attestation_required(&self) -> bool {
+        matches!(&self.enclave_config.attestation_pcrs, Some(pcrs) if !pcrs.is_empty())
+    }
+
+    fn rpc_sealed(&self, request: &schema::ApiRequest) -> Result<schema::ApiResponse, Error> {
+        match self.session.lock().unwrap().as_ref() {
+            Some(session) => {
+                let sealed = session
+                    .seal_request(request)
+                    .map_err(Error::AttestationError)?;
+                self.rpc(&sealed)
+            }
+            // No session established: only acceptable when attestation isn't
+            // configured at all. If PCRs are configured, sending this in
+            // plaintext would silently downgrade the sealed-channel contract
+            // (e.g. after a failed re-attestation on watchdog rebuild), so
+            // refuse instead.
+            None if self.attestation_required() => {
+                Err(Error::AttestationError(attestation::Error::SessionRequired))
+            }
+            None => self.rpc(request),
+        }
+    }
+
+    /// Request the enclave's NSM attestation document, verify its COSE
+    /// signature against the configured NSM root CA, check its PCRs and
+    /// handshake nonce, and derive a session key for sealing secret-bearing
+    /// RPC bodies. A verification failure or missing document aborts startup
+    /// by returning `false`. Attestation is opt-in: if no PCRs are
+    /// configured, this is a no-op and RPCs stay in plaintext.
+    fn establish_session(&self) -> bool {
+        let expected_pcrs = match &self.enclave_config.attestation_pcrs {
+            Some(pcrs) if !pcrs.is_empty() => pcrs,
+            _ => return true,
+        };
+
+        let root_cert_der = match self
+            .enclave_config
+            .nsm_root_cert_path
+            .as_ref()
+            .map(std::fs::read)
+        {
+            Some(Ok(bytes)) => bytes,
+            Some(Err(err)) => {
+                warn!("Failed to read NSM root certificate: {:?}", err);
+                return false;
+            }
+            None => {
+                warn!("Attestation is configured (PCRs set) but no nsm_root_cert_path is set");
+                return false;
+            }
+        };
+
+        let handshake = match attestation::Handshake::generate() {
+            Ok(handshake) => handshake,
+            Err(err) => {
+                warn!("Failed to generate host key agreement material: {:?}", err);
+                return false;
+            }
+        };
+
+        // The enclave needs our ephemeral public key to compute the same
+        // shared secret, and our nonce to prove the document it signs back
+        // is fresh, so both ride along in the Attest request body.
+        let raw_document = match self.rpc(&schema::ApiRequest::Attest {
+            host_public_key: handshake.public_bytes().to_vec(),
+            nonce: handshake.nonce().to_vec(),
+        }) {
+            Ok(Ok(schema::ApiResponseOk::Attest { document })) => document,
+            other => {
+                warn!("Attest RPC did not return an attestation document: {:?}", other);
+                return false;
+            }
+        };
+
+        match Session::establish(handshake, &raw_document, &root_cert_der, expected_pcrs) {
+            Ok(session) => {
+                *self.session.lock().unwrap() = Some(session);
+                true
+            }
+            Err(err) => {
+                warn!("Attestation validation failed: {:?}", err);
+                false
+            }
+        }
+    }
+
+    /// Tear down the current enclave process (if still alive) and rebuild a
+    /// fresh one from the original config, then replay every token that was
+    /// previously applied so the PKCS#11 surface comes back transparently.
+    /// If the rebuilt enclave does not become ready or fails re-attestation,
+    /// no token is replayed and `Ok(())` is still returned.
+    fn rebuild(&self) -> Result<(), Error> {
+        let old_pid = self.state.lock().unwrap().pid;
+        info!("Watchdog rebuilding enclave (old pid={})", old_pid);
+        graceful_kill(old_pid, self.shutdown_timeout);
+
+        let rebuilt = P11neEnclave::build(self.enclave_config.clone())?;
+        *self.state.lock().unwrap() = rebuilt.state.into_inner().unwrap();
+        self.start_console_reader();
+
+        // The rebuilt enclave is a new instance with a new attestation
+        // identity; the old session key no longer applies.
+        self.session.lock().unwrap().take();
+        if !self.wait_ready() || !self.establish_session() {
+            // Do not replay tokens without a sealed channel: if attestation
+            // is configured, `retry_rpc_sealed` would otherwise have no
+            // session to seal with and every PIN/envelope key in the replay
+            // would go out in plaintext.
+            warn!("Rebuilt enclave did not become ready or failed re-attestation; skipping token replay");
+            return Ok(());
+        }
+
+        let tokens = self.applied_tokens.lock().unwrap().clone();
+        info!("Replaying {} token(s) after enclave rebuild", tokens.len());
+        for token in tokens {
+            if let Err(err) =
+                self.retry_rpc_sealed(&schema::ApiRequest::AddToken { token: token.clone() })
+            {
+                warn!("Failed to replay token {:?}: {:?}", token, err);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl watchdog::Supervised for Inner {
+    fn cid(&self) -> u32 {
+        self.state.lock().unwrap().cid
+    }
+
+    fn probe_rpc(&self) -> bool {
+        matches!(self.rpc(&schema::ApiRequest::DescribeDevice), Ok(Ok(_)))
+    }
+
+    fn recover(&self) -> bool {
+        self.rebuild()
+            .map_err(|err| warn!("Enclave rebuild failed: {:?}", err))
+            .is_ok()
     }
 }
 
 impl Drop for P11neEnclave {
     fn drop(&mut self) {
-        info!("Killing enclave pid={}", self.pid());
-        signal::kill(unistd::Pid::from_raw(self.pid()), signal::Signal::SIGTERM)
-            .unwrap_or_default();
+        // Stop the watchdog first so it doesn't race a shutdown-in-progress
+        // enclave and try to "recover" it mid-drop.
+        self.watchdog.lock().unwrap().take();
+        self.inner.console.lock().unwrap().take();
+
+        graceful_kill(self.pid(), self.inner.shutdown_timeout);
+
         info!("Cleaning up p11kit config");
         std::fs::remove_file(format!(
             "/etc/pkcs11/modules/{}.module",
-            defs::P11_MODULE_NAME
+            self.inner.module_name
         ))
         .unwrap_or_else(|err| warn!("Cleanup error: {:?}", err));
+
+        info!("Restarting vsock proxy to deconfigure the stale enclave remote");
+        Command::new("systemctl")
+            .args(&["restart", "nitro-enclaves-vsock-proxy"])
+            .status()
+            .map_err(Error::SystemdExecError)
+            .and_then(|status| {
+                if status.success() {
+                    Ok(())
+                } else {
+                    Err(Error::VsockProxyError(status.code()))
+                }
+            })
+            .unwrap_or_else(|err| warn!("Failed to restart vsock proxy on teardown: {:?}", err));
+    }
+}
+
+/// Send `SIGTERM` and wait up to `timeout` for the process to exit, falling
+/// back to `SIGKILL` if it's still alive afterwards.
+fn graceful_kill(pid: i32, timeout: Duration) {
+    info!("Sending SIGTERM to enclave pid={}", pid);
+    if let Err(err) = signal::kill(unistd::Pid::from_raw(pid), signal::Signal::SIGTERM) {
+        warn!("Failed to SIGTERM enclave pid={}: {:?}", pid, err);
+        return;
+    }
+
+    let deadline = Instant::now() + timeout;
+    let poll = Duration::from_millis(50);
+    while Instant::now() < deadline {
+        if !process_alive(pid) {
+            info!("Enclave pid={} exited gracefully", pid);
+            return;
+        }
+        thread::sleep(poll);
+    }
+
+    warn!(
+        "Enclave pid={} did not exit within {:?}, escalating to SIGKILL",
+        pid, timeout
+    );
+    signal::kill(unistd::Pid::from_raw(pid), signal::Signal::SIGKILL).unwrap_or_default();
+
+    // Reap the process so a direct-child enclave doesn't linger as a zombie;
+    // an ECHILD here just means it was already reaped (e.g. reparented).
+    match waitpid(unistd::Pid::from_raw(pid), None) {
+        Ok(_) | Err(nix::Error::ECHILD) => {}
+        Err(err) => warn!("Failed to reap enclave pid={} after SIGKILL: {:?}", pid, err),
+    }
+}
+
+fn process_alive(pid: i32) -> bool {
+    match waitpid(unistd::Pid::from_raw(pid), Some(WaitPidFlag::WNOHANG)) {
+        Ok(WaitStatus::StillAlive) => true,
+        Ok(_) => false,
+        // Not our child (e.g. reparented to init): fall back to a signal-0 probe.
+        Err(nix::Error::ECHILD) => signal::kill(unistd::Pid::from_raw(pid), None).is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{graceful_kill, process_alive};
+    use nix::sys::wait::{waitpid, WaitPidFlag};
+    use nix::unistd;
+    use std::process::Command;
+    use std::time::Duration;
+
+    #[test]
+    fn process_alive_reflects_real_child_state() {
+        let mut child = Command::new("sleep").arg("5").spawn().expect("spawn sleep");
+        let pid = child.id() as i32;
+        assert!(process_alive(pid));
+
+        child.kill().expect("kill sleep");
+        child.wait().expect("reap sleep");
+        assert!(!process_alive(pid));
+    }
+
+    #[test]
+    fn graceful_kill_reaps_a_child_that_ignores_sigterm() {
+        // Ignore SIGTERM so graceful_kill is forced down the SIGKILL path;
+        // a short shutdown_timeout keeps the test fast.
+        let child = Command::new("sh")
+            .args(["-c", "trap '' TERM; sleep 5"])
+            .spawn()
+            .expect("spawn stubborn child");
+        let pid = child.id() as i32;
+        assert!(process_alive(pid));
+
+        graceful_kill(pid, Duration::from_millis(100));
+
+        // graceful_kill must have reaped the child itself: a zombie would
+        // still be waitable here, whereas a reaped pid makes waitpid fail
+        // with ECHILD.
+        assert!(matches!(
+            waitpid(unistd::Pid::from_raw(pid), Some(WaitPidFlag::WNOHANG)),
+            Err(nix::Error::ECHILD)
+        ));
+        assert!(!process_alive(pid));
+        assert!(!process_alive(pid));
     }
 }
@@ -0,0 +1,182 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+//! Verification of NSM (Nitro Security Module) attestation documents.
+//!
+//! The document the enclave hands back from its `Attest` call is a
+//! CBOR-encoded COSE_Sign1 structure: PCR measurements and the enclave's
+//! ephemeral public key, signed by a certificate whose chain bottoms out at
+//! the AWS Nitro Enclaves root CA. On its own, a document's PCR/public-key
+//! *claims* prove nothing — the EIF (and therefore its PCRs) are public, so
+//! anyone can construct a lookalike payload. What actually roots trust is
+//! the COSE signature and the certificate chain behind it; `verify` checks
+//! both, plus a caller-supplied nonce to rule out replay of a previously
+//! captured document, before returning any of the document's contents.
+use ring::signature;
+use serde_cbor::Value;
+use std::collections::BTreeMap;
+
+#[derive(Debug)]
+pub enum Error {
+    Cbor(serde_cbor::Error),
+    MalformedCoseSign1,
+    MalformedCertificate,
+    ChainVerificationFailed,
+    SignatureVerificationFailed,
+    NonceMismatch,
+    MissingField(&'static str),
+}
+
+pub struct VerifiedDocument {
+    pub pcrs: BTreeMap<usize, Vec<u8>>,
+    pub public_key: Vec<u8>,
+}
+
+/// Verify `raw_document` (the bytes returned by the enclave's NSM `Attest`
+/// call) against `root_cert_der` and `expected_nonce`, returning the
+/// verified PCRs and ephemeral public key on success. Every step (chain,
+/// signature, nonce) must pass — none of the document's fields are trusted
+/// before all three do.
+pub fn verify(
+    raw_document: &[u8],
+    root_cert_der: &[u8],
+    expected_nonce: &[u8],
+) -> Result<VerifiedDocument, Error> {
+    // COSE_Sign1 = [protected: bstr, unprotected: map, payload: bstr, signature: bstr]
+    let cose: Vec<Value> = match serde_cbor::from_slice(raw_document).map_err(Error::Cbor)? {
+        Value::Array(items) if items.len() == 4 => items,
+        _ => return Err(Error::MalformedCoseSign1),
+    };
+    let protected = as_bytes(&cose[0]).ok_or(Error::MalformedCoseSign1)?;
+    let payload_bytes = as_bytes(&cose[2]).ok_or(Error::MalformedCoseSign1)?;
+    let signature_bytes = as_bytes(&cose[3]).ok_or(Error::MalformedCoseSign1)?;
+
+    let payload: BTreeMap<String, Value> =
+        match serde_cbor::from_slice(payload_bytes).map_err(Error::Cbor)? {
+            Value::Map(map) => map
+                .into_iter()
+                .filter_map(|(k, v)| match k {
+                    Value::Text(key) => Some((key, v)),
+                    _ => None,
+                })
+                .collect(),
+            _ => return Err(Error::MalformedCoseSign1),
+        };
+
+    let leaf_cert = field_bytes(&payload, "certificate")?;
+    let cabundle = match payload.get("cabundle") {
+        Some(Value::Array(items)) => items
+            .iter()
+            .map(|v| as_bytes(v).map(|b| b.to_vec()).ok_or(Error::MalformedCertificate))
+            .collect::<Result<Vec<_>, _>>()?,
+        _ => Vec::new(),
+    };
+
+    let leaf_public_key = verify_cert_chain(leaf_cert, &cabundle, root_cert_der)?;
+
+    // Sig_structure per RFC 8152 §4.4, context "Signature1", empty external_aad.
+    let sig_structure = serde_cbor::to_vec(&Value::Array(vec![
+        Value::Text("Signature1".to_string()),
+        Value::Bytes(protected.to_vec()),
+        Value::Bytes(Vec::new()),
+        Value::Bytes(payload_bytes.to_vec()),
+    ]))
+    .map_err(Error::Cbor)?;
+
+    // COSE carries ECDSA signatures as fixed-width r||s, not ASN.1 DER.
+    signature::UnparsedPublicKey::new(&signature::ECDSA_P384_SHA384_FIXED, &leaf_public_key)
+        .verify(&sig_structure, signature_bytes)
+        .map_err(|_| Error::SignatureVerificationFailed)?;
+
+    let nonce = field_bytes(&payload, "nonce")?;
+    if nonce != expected_nonce {
+        return Err(Error::NonceMismatch);
+    }
+
+    let public_key = field_bytes(&payload, "public_key")?.to_vec();
+    let pcrs = match payload.get("pcrs") {
+        Some(Value::Map(map)) => map
+            .iter()
+            .filter_map(|(k, v)| {
+                let index = match k {
+                    Value::Integer(i) => usize::try_from(*i).ok(),
+                    _ => None,
+                }?;
+                as_bytes(v).map(|b| (index, b.to_vec()))
+            })
+            .collect(),
+        _ => return Err(Error::MissingField("pcrs")),
+    };
+
+    Ok(VerifiedDocument { pcrs, public_key })
+}
+
+/// Verify that `leaf_cert` chains up through `cabundle` to exactly
+/// `root_cert_der`, checking each link's signature along the way, and
+/// return the leaf certificate's public key bytes.
+///
+/// This pins the root by exact DER match rather than walking a trust store,
+/// since the AWS Nitro Enclaves root is a single well-known certificate we
+/// ship alongside the agent config (`config::Enclave::nsm_root_cert_path`).
+fn verify_cert_chain(
+    leaf_cert: &[u8],
+    cabundle: &[Vec<u8>],
+    root_cert_der: &[u8],
+) -> Result<Vec<u8>, Error> {
+    // cabundle is ordered root-first, so the chain to verify is
+    // [cabundle[0]==root, ..., cabundle[last], leaf].
+    let root = cabundle.first().map(|v| v.as_slice()).unwrap_or(leaf_cert);
+    if root != root_cert_der {
+        return Err(Error::ChainVerificationFailed);
+    }
+
+    let mut chain: Vec<&[u8]> = cabundle.iter().map(|v| v.as_slice()).collect();
+    chain.push(leaf_cert);
+
+    for pair in chain.windows(2) {
+        let (issuer_der, subject_der) = (pair[0], pair[1]);
+        let issuer_public_key = x509_public_key(issuer_der)?;
+        let (tbs, sig) = x509_tbs_and_signature(subject_der)?;
+        signature::UnparsedPublicKey::new(&signature::ECDSA_P384_SHA384_ASN1, &issuer_public_key)
+            .verify(tbs, sig)
+            .map_err(|_| Error::ChainVerificationFailed)?;
+    }
+
+    x509_public_key(leaf_cert)
+}
+
+fn as_bytes(value: &Value) -> Option<&[u8]> {
+    match value {
+        Value::Bytes(b) => Some(b),
+        _ => None,
+    }
+}
+
+fn field_bytes<'a>(
+    payload: &'a BTreeMap<String, Value>,
+    field: &'static str,
+) -> Result<&'a [u8], Error> {
+    payload
+        .get(field)
+        .and_then(as_bytes)
+        .ok_or(Error::MissingField(field))
+}
+
+/// Extract the subjectPublicKeyInfo's raw key bytes from a DER-encoded X.509
+/// certificate. NSM certificates are always EC (P-384), so this expects an
+/// uncompressed EC point rather than parsing arbitrary `AlgorithmIdentifier`s.
+fn x509_public_key(cert_der: &[u8]) -> Result<Vec<u8>, Error> {
+    x509_parser::parse_x509_certificate(cert_der)
+        .map(|(_, cert)| cert.public_key().subject_public_key.data.to_vec())
+        .map_err(|_| Error::MalformedCertificate)
+}
+
+/// Extract the tbsCertificate bytes and the outer signature bytes, i.e. the
+/// two halves needed to verify that `issuer` actually signed `subject`.
+fn x509_tbs_and_signature(cert_der: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+    let (_, cert) =
+        x509_parser::parse_x509_certificate(cert_der).map_err(|_| Error::MalformedCertificate)?;
+    Ok((
+        cert.tbs_certificate.as_ref(),
+        cert.signature_value.data.as_ref(),
+    ))
+}
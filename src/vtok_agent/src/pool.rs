@@ -0,0 +1,204 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+use log::{info, warn};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::defs;
+use super::enclave::{Error, P11neEnclave};
+use crate::config;
+use vtok_rpc::api::schema;
+
+/// A pool of replica enclaves, all launched from the same `config::Enclave`,
+/// each with its own CID and p11-kit module entry. Write operations
+/// (`AddToken`/`UpdateToken`/`RefreshToken`/`RemoveToken`) are broadcast to
+/// every replica so they stay in sync; reads (`DescribeToken`) can be served
+/// by any healthy replica, so a single enclave crash or reboot no longer
+/// means an outage for dependent TLS services.
+///
+/// Per-replica liveness and recovery are handled by each `P11neEnclave`'s own
+/// watchdog (see `config::Enclave::watchdog_enabled`), so a failed replica
+/// rebuilds itself without the pool needing to know.
+pub struct P11neEnclavePool {
+    replicas: Vec<P11neEnclave>,
+    next_read_replica: AtomicUsize,
+}
+
+impl P11neEnclavePool {
+    pub fn new(enclave_config: config::Enclave) -> Result<Self, Error> {
+        let replica_count = enclave_config
+            .pool_replica_count
+            .unwrap_or(defs::DEFAULT_POOL_REPLICA_COUNT);
+        Self::with_replica_count(enclave_config, replica_count)
+    }
+
+    pub fn with_replica_count(
+        enclave_config: config::Enclave,
+        replica_count: usize,
+    ) -> Result<Self, Error> {
+        let replica_count = replica_count.max(1);
+        let base_module_name = enclave_config
+            .p11_module_name
+            .clone()
+            .unwrap_or_else(|| defs::P11_MODULE_NAME.to_string());
+
+        let mut replicas = Vec::with_capacity(replica_count);
+        for idx in 0..replica_count {
+            let mut replica_config = enclave_config.clone();
+            replica_config.p11_module_name = Some(format!("{}-{}", base_module_name, idx));
+            info!("Launching pool replica {}/{}", idx + 1, replica_count);
+            replicas.push(P11neEnclave::new(replica_config)?);
+        }
+
+        Ok(P11neEnclavePool {
+            replicas,
+            next_read_replica: AtomicUsize::new(0),
+        })
+    }
+
+    /// Waits for every replica to finish booting (and, if configured,
+    /// attesting). Returns `false` if any replica fails to come up.
+    pub fn wait_boot(&self) -> bool {
+        self.replicas.iter().all(|replica| replica.wait_boot())
+    }
+
+    /// Applies `op` to every replica so writes stay in sync across the pool.
+    /// Every replica is attempted even after a failure, so one bad replica
+    /// doesn't stop the others from being updated — but the first failure is
+    /// retained and returned rather than being overwritten by a later
+    /// success, since a replica that silently diverged from the rest of the
+    /// pool needs to surface to the caller.
+    fn broadcast(
+        &self,
+        op: impl Fn(&P11neEnclave) -> Result<schema::ApiResponse, Error>,
+    ) -> Result<schema::ApiResponse, Error> {
+        broadcast_all(self.replicas.len(), |idx| {
+            op(&self.replicas[idx]).map_err(|err| {
+                warn!("Replica operation failed: {:?}", err);
+                err
+            })
+        })
+    }
+
+    pub fn add_token(&self, token: schema::Token) -> Result<schema::ApiResponse, Error> {
+        self.broadcast(|replica| replica.add_token(token.clone()))
+    }
+
+    pub fn refresh_token(
+        &self,
+        label: String,
+        pin: String,
+        envelope_key: schema::EnvelopeKey,
+    ) -> Result<schema::ApiResponse, Error> {
+        self.broadcast(|replica| {
+            replica.refresh_token(label.clone(), pin.clone(), envelope_key.clone())
+        })
+    }
+
+    pub fn remove_token(&self, label: String, pin: String) -> Result<schema::ApiResponse, Error> {
+        self.broadcast(|replica| replica.remove_token(label.clone(), pin.clone()))
+    }
+
+    pub fn update_token(
+        &self,
+        label: String,
+        pin: String,
+        token: schema::Token,
+    ) -> Result<schema::ApiResponse, Error> {
+        self.broadcast(|replica| replica.update_token(label.clone(), pin.clone(), token.clone()))
+    }
+
+    /// Reads can be served by any healthy replica; round-robin spreads them
+    /// across the pool, starting at the next replica each call. If the
+    /// chosen replica errors (e.g. it's mid-rebuild after a watchdog
+    /// recovery), fall over to the next one instead of surfacing the error
+    /// straight to the caller — only once every replica has failed is the
+    /// last error returned.
+    pub fn describe_token(&self, label: String, pin: String) -> Result<schema::ApiResponse, Error> {
+        let start = self.next_read_replica.fetch_add(1, Ordering::Relaxed) % self.replicas.len();
+        let mut last_err = None;
+        for offset in 0..self.replicas.len() {
+            let idx = (start + offset) % self.replicas.len();
+            match self.replicas[idx].describe_token(label.clone(), pin.clone()) {
+                Ok(res) => return Ok(res),
+                Err(err) => {
+                    warn!("Replica {} failed to serve DescribeToken: {:?}", idx, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.expect("pool is never empty"))
+    }
+}
+
+/// Applies `op` to every index in `0..len`, trying all of them even after a
+/// failure. The first error encountered is retained and returned over any
+/// later success; only if every call succeeds is the last success returned.
+/// Pulled out of `broadcast` as a plain function so the error-vs-success
+/// precedence can be unit tested without standing up real replicas.
+fn broadcast_all<T, E>(len: usize, op: impl Fn(usize) -> Result<T, E>) -> Result<T, E> {
+    let mut first_err = None;
+    let mut last_ok = None;
+    for idx in 0..len {
+        match op(idx) {
+            Ok(res) => last_ok = Some(res),
+            Err(err) => {
+                if first_err.is_none() {
+                    first_err = Some(err);
+                }
+            }
+        }
+    }
+    match first_err {
+        Some(err) => Err(err),
+        None => Ok(last_ok.expect("len > 0")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::broadcast_all;
+
+    #[test]
+    fn all_ok_returns_last_success() {
+        let calls = std::cell::RefCell::new(Vec::new());
+        let result = broadcast_all(3, |idx| {
+            calls.borrow_mut().push(idx);
+            Ok::<_, &str>(idx)
+        });
+        assert_eq!(result, Ok(2));
+        assert_eq!(*calls.borrow(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn a_middle_failure_is_not_masked_by_a_later_success() {
+        // [ok, err, ok] must surface the err, not the trailing ok.
+        let result = broadcast_all(3, |idx| if idx == 1 { Err("replica 1 failed") } else { Ok(idx) });
+        assert_eq!(result, Err("replica 1 failed"));
+    }
+
+    #[test]
+    fn every_index_is_attempted_even_after_a_failure() {
+        let calls = std::cell::RefCell::new(Vec::new());
+        let _ = broadcast_all(3, |idx| {
+            calls.borrow_mut().push(idx);
+            if idx == 0 {
+                Err("replica 0 failed")
+            } else {
+                Ok(idx)
+            }
+        });
+        assert_eq!(*calls.borrow(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn first_failure_wins_over_a_second_failure() {
+        let result = broadcast_all(3, |idx| {
+            if idx == 0 || idx == 2 {
+                Err(idx)
+            } else {
+                Ok(idx)
+            }
+        });
+        assert_eq!(result, Err(0));
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+use log::warn;
+use std::sync::Mutex;
+
+use vtok_rpc::api::schema;
+use vtok_rpc::{HttpTransport, Transport, VsockAddr, VsockStream};
+
+#[derive(Debug)]
+pub enum TransportCallError {
+    Connect(std::io::Error),
+    Transport(vtok_rpc::TransportError),
+}
+
+/// A persistent, lazily-reconnecting RPC client.
+///
+/// Per-call `connect()` dominates latency for chatty paths like the
+/// `wait_boot` poll loop and periodic `RefreshToken` calls, so this keeps a
+/// single `HttpTransport` alive across calls instead of opening a fresh
+/// `VsockStream` every time. The connection is torn down and re-established
+/// whenever the target `(cid, port)` changes (e.g. after a watchdog rebuild)
+/// or whenever a call fails.
+///
+/// On a broken-pipe-style failure we don't know whether the enclave received
+/// and applied the request before the connection died — only whether *we*
+/// saw a response. For an idempotent request (reads, `Attest`) that's fine to
+/// paper over with a transparent reconnect-and-resend, since applying it
+/// twice is harmless. For a mutating request (`AddToken`/`UpdateToken`/etc.,
+/// and any `Sealed` request, since we can't see what's wrapped inside the
+/// ciphertext) a blind resend risks double-applying a PIN change or token
+/// add, so those get one reconnect to leave the connection usable for the
+/// *next* call, but the failed call itself is reported to the caller rather
+/// than silently retried — i.e. at-most-once, not at-least-once, for
+/// anything non-idempotent.
+pub struct RpcClient {
+    conn: Mutex<Option<(u32, u32, HttpTransport<VsockStream>)>>,
+}
+
+impl RpcClient {
+    pub fn new() -> Self {
+        RpcClient {
+            conn: Mutex::new(None),
+        }
+    }
+
+    /// Send `request` to `cid:port`, reusing the open connection when
+    /// possible. Concurrent callers serialize on the same connection via the
+    /// internal mutex.
+    pub fn call(
+        &self,
+        cid: u32,
+        port: u32,
+        request: &schema::ApiRequest,
+    ) -> Result<schema::ApiResponse, TransportCallError> {
+        let mut guard = self.conn.lock().unwrap();
+
+        let stale = match &*guard {
+            Some((conn_cid, conn_port, _)) => *conn_cid != cid || *conn_port != port,
+            None => true,
+        };
+        if stale {
+            *guard = Some((cid, port, Self::connect(cid, port)?));
+        }
+
+        match Self::send(&mut guard.as_mut().unwrap().2, request) {
+            Ok(res) => Ok(res),
+            Err(err) => {
+                warn!(
+                    "Persistent RPC connection to cid={} port={} failed ({:?}), reconnecting",
+                    cid, port, err
+                );
+                *guard = Some((cid, port, Self::connect(cid, port)?));
+                if Self::is_idempotent(request) {
+                    Self::send(&mut guard.as_mut().unwrap().2, request)
+                } else {
+                    Err(err)
+                }
+            }
+        }
+    }
+
+    /// Whether replaying `request` after a lost response is safe, i.e.
+    /// whether applying it twice has the same effect as applying it once.
+    /// `Sealed` wraps an opaque ciphertext we can't inspect, so it's treated
+    /// as non-idempotent even though the request it carries might happen to
+    /// be a read.
+    fn is_idempotent(request: &schema::ApiRequest) -> bool {
+        matches!(
+            request,
+            schema::ApiRequest::DescribeDevice
+                | schema::ApiRequest::DescribeToken { .. }
+                | schema::ApiRequest::Attest { .. }
+        )
+    }
+
+    fn connect(cid: u32, port: u32) -> Result<HttpTransport<VsockStream>, TransportCallError> {
+        VsockStream::connect(VsockAddr { cid, port })
+            .map(|stream| HttpTransport::new(stream, schema::API_URL))
+            .map_err(TransportCallError::Connect)
+    }
+
+    fn send(
+        xport: &mut HttpTransport<VsockStream>,
+        request: &schema::ApiRequest,
+    ) -> Result<schema::ApiResponse, TransportCallError> {
+        xport
+            .send_request(request)
+            .map_err(TransportCallError::Transport)?;
+        xport.recv_response().map_err(TransportCallError::Transport)
+    }
+}
+
+impl Default for RpcClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,181 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+use log::{info, warn};
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use super::defs;
+use vtok_rpc::{VsockAddr, VsockStream};
+
+/// The single byte written by the host and echoed back by the enclave once
+/// its PKCS#11 agent is up. The value itself is arbitrary — it just has to
+/// match what the enclave's echo server expects — chosen so the probe stays
+/// cheap and doesn't need to speak HTTP.
+const HEARTBEAT_BYTE: u8 = 0xB7;
+
+/// How the watchdog checks whether the enclave is still alive.
+#[derive(Debug, Clone, Copy)]
+pub enum ProbeMode {
+    /// Issue a full `DescribeDevice` RPC over the existing HTTP transport.
+    Rpc,
+    /// Open a raw vsock connection to `heartbeat_port` and exchange a single
+    /// byte. Much cheaper than paying for an HTTP round-trip on every poll.
+    Heartbeat,
+}
+
+/// Parameters controlling how often and how aggressively the watchdog
+/// reacts to a dead enclave.
+#[derive(Debug, Clone)]
+pub struct WatchdogConfig {
+    pub probe_mode: ProbeMode,
+    pub probe_interval: Duration,
+    pub failure_threshold: u32,
+    pub heartbeat_port: u32,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        WatchdogConfig {
+            probe_mode: ProbeMode::Rpc,
+            probe_interval: Duration::from_millis(defs::DEFAULT_WATCHDOG_PROBE_INTERVAL_MS),
+            failure_threshold: defs::DEFAULT_WATCHDOG_FAILURE_THRESHOLD,
+            heartbeat_port: defs::DEFAULT_WATCHDOG_HEARTBEAT_PORT,
+        }
+    }
+}
+
+/// Anything the watchdog needs to do to the supervised enclave. Kept as a
+/// trait so the polling/recovery loop doesn't need to know about
+/// `P11neEnclave`'s internals.
+pub trait Supervised: Send + Sync {
+    /// Vsock CID of the enclave being probed.
+    fn cid(&self) -> u32;
+    /// `true` if an RPC-based probe succeeded.
+    fn probe_rpc(&self) -> bool;
+    /// Tear the enclave down and rebuild it, replaying any state (e.g.
+    /// previously-applied tokens) that needs to survive the rebuild.
+    fn recover(&self) -> bool;
+}
+
+/// Background liveness watchdog for a single enclave. Owns a thread that
+/// polls the enclave and triggers `Supervised::recover()` after
+/// `failure_threshold` consecutive failed probes.
+pub struct Watchdog {
+    stop: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl Watchdog {
+    pub fn spawn<S: Supervised + 'static>(supervised: Arc<S>, config: WatchdogConfig) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let handle = thread::spawn(move || Self::run(supervised, config, thread_stop));
+        Watchdog {
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    fn run<S: Supervised>(supervised: Arc<S>, config: WatchdogConfig, stop: Arc<AtomicBool>) {
+        let mut consecutive_failures = 0u32;
+        while !stop.load(Ordering::Relaxed) {
+            thread::sleep(config.probe_interval);
+            if stop.load(Ordering::Relaxed) {
+                break;
+            }
+
+            let alive = match config.probe_mode {
+                ProbeMode::Rpc => supervised.probe_rpc(),
+                ProbeMode::Heartbeat => {
+                    Self::probe_heartbeat(supervised.cid(), config.heartbeat_port)
+                }
+            };
+
+            if alive {
+                consecutive_failures = 0;
+                continue;
+            }
+
+            consecutive_failures += 1;
+            warn!(
+                "Watchdog probe failed ({}/{} consecutive failures)",
+                consecutive_failures, config.failure_threshold
+            );
+
+            if Self::threshold_reached(&mut consecutive_failures, config.failure_threshold) {
+                info!("Watchdog triggering enclave recovery after repeated probe failures");
+                if supervised.recover() {
+                    info!("Watchdog recovery succeeded");
+                } else {
+                    warn!("Watchdog recovery failed; will keep probing");
+                }
+            }
+        }
+    }
+
+    /// If `consecutive_failures` has reached `failure_threshold`, reset it
+    /// and report that recovery should fire; otherwise leave it as-is. Kept
+    /// as a plain function (no `&self`, no I/O) so the thresholding/reset
+    /// behavior is unit testable without spinning up a probe thread.
+    fn threshold_reached(consecutive_failures: &mut u32, failure_threshold: u32) -> bool {
+        if *consecutive_failures >= failure_threshold {
+            *consecutive_failures = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn probe_heartbeat(cid: u32, port: u32) -> bool {
+        VsockStream::connect(VsockAddr { cid, port })
+            .and_then(|mut stream| {
+                stream.write_all(&[HEARTBEAT_BYTE])?;
+                let mut reply = [0u8; 1];
+                stream.read_exact(&mut reply)?;
+                Ok(reply[0] == HEARTBEAT_BYTE)
+            })
+            .unwrap_or(false)
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            handle.join().unwrap_or_default();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Watchdog;
+
+    #[test]
+    fn does_not_fire_or_reset_below_threshold() {
+        let mut failures = 2;
+        assert!(!Watchdog::threshold_reached(&mut failures, 3));
+        assert_eq!(failures, 2);
+    }
+
+    #[test]
+    fn fires_exactly_at_threshold_and_resets() {
+        let mut failures = 3;
+        assert!(Watchdog::threshold_reached(&mut failures, 3));
+        assert_eq!(failures, 0);
+    }
+
+    #[test]
+    fn does_not_fire_again_until_threshold_is_reached_once_more() {
+        let mut failures = 3;
+        assert!(Watchdog::threshold_reached(&mut failures, 3));
+        assert!(!Watchdog::threshold_reached(&mut failures, 3));
+        failures += 2;
+        assert!(!Watchdog::threshold_reached(&mut failures, 3));
+        failures += 1;
+        assert!(Watchdog::threshold_reached(&mut failures, 3));
+    }
+}